@@ -3,12 +3,17 @@
 //! This API is intended to be used by nix-installer-action.
 
 use attic::nix_store::StorePath;
-use axum::{extract::Extension, routing::post, Json, Router};
+use axum::{
+    extract::Extension,
+    routing::{get, post},
+    Json, Router,
+};
 use axum_macros::debug_handler;
 use serde::{Deserialize, Serialize};
 
 use super::State;
 use crate::error::{Error, Result};
+use crate::jobs::{BackendSummary, QueueStatus};
 
 #[derive(Debug, Clone, Serialize)]
 struct WorkflowStartResponse {
@@ -20,6 +25,8 @@ struct WorkflowFinishResponse {
     num_original_paths: usize,
     num_final_paths: usize,
     num_new_paths: usize,
+    gha_cache: BackendSummary,
+    flakehub: BackendSummary,
 }
 
 pub fn get_router() -> Router {
@@ -27,6 +34,19 @@ pub fn get_router() -> Router {
         .route("/api/workflow-start", post(workflow_start))
         .route("/api/workflow-finish", post(workflow_finish))
         .route("/api/enqueue-paths", post(post_enqueue_paths))
+        .route("/api/status", get(get_status))
+}
+
+/// Report current upload queue depth, in-flight uploads, and cumulative
+/// per-backend counts, so the action can surface progress while a workflow
+/// is still running.
+async fn get_status(Extension(state): Extension<State>) -> Result<Json<QueueStatus>> {
+    let status = match state.job_queue.lock().await.as_ref() {
+        Some(job_queue) => job_queue.status(),
+        None => QueueStatus::default(),
+    };
+
+    Ok(Json(status))
 }
 
 /// Record existing paths.
@@ -54,13 +74,25 @@ async fn workflow_finish(
 ) -> Result<Json<WorkflowFinishResponse>> {
     tracing::info!("Workflow finished");
 
+    // Paths the store watcher already caught as they were built don't need
+    // to be re-enqueued from the store diff below.
+    let watcher_seen = if let Some(watcher) = state.store_watcher.lock().await.take() {
+        tracing::info!("Stopping store watcher");
+        watcher.shutdown().await
+    } else {
+        Default::default()
+    };
+
     let original_paths = state.original_paths.lock().await;
     let final_paths = crate::util::get_store_paths(&state.store).await?;
     let new_paths = final_paths
         .difference(&original_paths)
         .cloned()
         .map(|path| state.store.follow_store_path(path).map_err(Error::Attic))
-        .collect::<Result<Vec<_>>>()?;
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .filter(|path| !watcher_seen.contains(path))
+        .collect::<Vec<_>>();
 
     let num_original_paths = original_paths.len();
     let num_final_paths = final_paths.len();
@@ -71,10 +103,12 @@ async fn workflow_finish(
     tracing::info!("Diffing the store and uploading any new paths before we shut down");
     enqueue_paths(&state, new_paths).await?;
 
-    if let Some(gha_cache) = &state.gha_cache {
-        tracing::info!("Waiting for GitHub action cache uploads to finish");
-        gha_cache.shutdown().await?;
-    }
+    let queue_status = if let Some(job_queue) = state.job_queue.lock().await.take() {
+        tracing::info!("Waiting for upload queue to drain");
+        job_queue.shutdown(&state).await?
+    } else {
+        QueueStatus::default()
+    };
 
     if let Some(sender) = state.shutdown_sender.lock().await.take() {
         sender
@@ -82,12 +116,6 @@ async fn workflow_finish(
             .map_err(|_| Error::Internal("Sending shutdown server message".to_owned()))?;
     }
 
-    if let Some(attic_state) = state.flakehub_state.write().await.take() {
-        tracing::info!("Waiting for FlakeHub cache uploads to finish");
-        let paths = attic_state.push_session.wait().await?;
-        tracing::warn!(?paths, "pushed these paths");
-    }
-
     // NOTE(cole-h): see `init_logging`
     let logfile = std::env::temp_dir().join("magic-nix-cache-tracing.log");
     let logfile_contents = std::fs::read_to_string(logfile)?;
@@ -98,6 +126,8 @@ async fn workflow_finish(
         num_original_paths,
         num_final_paths,
         num_new_paths,
+        gha_cache: queue_status.gha_cache,
+        flakehub: queue_status.flakehub,
     };
 
     state
@@ -113,10 +143,25 @@ async fn workflow_finish(
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EnqueuePathsRequest {
     pub store_paths: Vec<String>,
+
+    /// Upload exactly the requested paths instead of expanding them to their
+    /// full closure first.
+    #[serde(default)]
+    pub no_closure: bool,
+
+    /// Upload paths even if they're already present in a configured
+    /// upstream cache.
+    #[serde(default)]
+    pub ignore_upstream_cache_filter: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct EnqueuePathsResponse {}
+pub struct EnqueuePathsResponse {
+    pub num_requested: usize,
+    pub num_after_closure: usize,
+    pub num_skipped_upstream: usize,
+    pub num_enqueued: usize,
+}
 
 /// Schedule paths in the local Nix store for uploading.
 #[tracing::instrument(skip_all)]
@@ -132,22 +177,60 @@ async fn post_enqueue_paths(
         .map(|path| state.store.follow_store_path(path).map_err(Error::Attic))
         .collect::<Result<Vec<_>>>()?;
 
-    enqueue_paths(&state, store_paths).await?;
+    let plan = enqueue_paths_with_plan(
+        &state,
+        store_paths,
+        req.no_closure,
+        req.ignore_upstream_cache_filter,
+    )
+    .await?;
+
+    Ok(Json(EnqueuePathsResponse {
+        num_requested: plan.num_requested,
+        num_after_closure: plan.num_after_closure,
+        num_skipped_upstream: plan.num_skipped_upstream,
+        num_enqueued: plan.num_enqueued(),
+    }))
+}
+
+pub(crate) async fn enqueue_paths(state: &State, store_paths: Vec<StorePath>) -> Result<()> {
+    // `store_paths` here is already the exhaustive `workflow_finish` store
+    // diff, not a set of roots — expanding it to its closure would pull in
+    // pre-existing runtime deps of the new outputs and re-upload them.
+    enqueue_paths_with_plan(state, store_paths, true, false).await?;
 
-    Ok(Json(EnqueuePathsResponse {}))
+    Ok(())
 }
 
-async fn enqueue_paths(state: &State, store_paths: Vec<StorePath>) -> Result<()> {
-    if let Some(gha_cache) = &state.gha_cache {
-        gha_cache
-            .enqueue_paths(state.store.clone(), store_paths.clone())
-            .await?;
+/// Push paths straight onto the job queue, skipping closure expansion and
+/// the upstream-cache filter. Used by the live store watcher, which already
+/// knows exactly which single path just finished building and would
+/// otherwise redo that planning work on every settled path.
+pub(crate) async fn enqueue_paths_raw(state: &State, store_paths: Vec<StorePath>) -> Result<()> {
+    if let Some(job_queue) = state.job_queue.lock().await.as_ref() {
+        job_queue.enqueue(store_paths).await?;
     }
 
-    if let Some(flakehub_state) = &*state.flakehub_state.read().await {
-        tracing::warn!("enqueuing {:?} for flakehub", store_paths);
-        crate::flakehub::enqueue_paths(flakehub_state, store_paths).await?;
+    Ok(())
+}
+
+async fn enqueue_paths_with_plan(
+    state: &State,
+    store_paths: Vec<StorePath>,
+    no_closure: bool,
+    ignore_upstream_cache_filter: bool,
+) -> Result<crate::plan::Plan> {
+    let (plan, store_paths) = crate::plan::plan(
+        state,
+        store_paths,
+        no_closure,
+        ignore_upstream_cache_filter,
+    )
+    .await?;
+
+    if let Some(job_queue) = state.job_queue.lock().await.as_ref() {
+        job_queue.enqueue(store_paths).await?;
     }
 
-    Ok(())
+    Ok(plan)
 }
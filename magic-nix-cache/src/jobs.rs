@@ -0,0 +1,268 @@
+//! A bounded, deduplicating upload queue backed by a fixed pool of worker
+//! tasks. Progress is tracked through `state.metrics`, the same gauges
+//! `api.rs` already sets elsewhere, so `/api/status` and `workflow-finish`
+//! read from one source of truth instead of a second counter set.
+
+use std::{collections::HashSet, sync::Arc};
+
+use attic::nix_store::StorePath;
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::error::{Error, Result};
+use crate::State;
+
+/// Number of worker tasks pulling jobs off the queue concurrently.
+const WORKERS: usize = 8;
+
+/// How many pending jobs may sit in the queue before `enqueue` backs off.
+const QUEUE_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone)]
+struct Job {
+    store_path: StorePath,
+}
+
+/// Filters `items` down to those not already present in `submitted`,
+/// inserting each kept item as it goes.
+fn dedup<T: std::hash::Hash + Eq + Clone>(submitted: &mut HashSet<T>, items: Vec<T>) -> Vec<T> {
+    items
+        .into_iter()
+        .filter(|item| submitted.insert(item.clone()))
+        .collect()
+}
+
+/// Path counts for a single backend: how many were enqueued, uploaded,
+/// skipped because they were already submitted, or failed.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct BackendSummary {
+    pub enqueued: usize,
+    pub uploaded: usize,
+    pub skipped_duplicate: usize,
+    pub failed: usize,
+}
+
+/// A point-in-time snapshot of queue activity, used by both `workflow-finish`
+/// and `GET /api/status`.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct QueueStatus {
+    pub queue_depth: usize,
+    pub in_flight: usize,
+    pub bytes_pushed: u64,
+    pub gha_cache: BackendSummary,
+    pub flakehub: BackendSummary,
+}
+
+/// A bounded, deduplicating queue of upload jobs, backed by a fixed pool of
+/// worker tasks that dispatch each job to every configured backend.
+pub struct JobQueue {
+    sender: async_channel::Sender<Job>,
+    submitted: Arc<Mutex<HashSet<StorePath>>>,
+    workers: Vec<tokio::task::JoinHandle<()>>,
+    state: State,
+}
+
+impl JobQueue {
+    /// Spawn the worker pool.
+    pub fn new(state: State) -> Self {
+        let (sender, receiver) = async_channel::bounded(QUEUE_CAPACITY);
+
+        let workers = (0..WORKERS)
+            .map(|_| {
+                let state = state.clone();
+                let receiver = receiver.clone();
+                tokio::task::spawn(async move {
+                    while let Ok(job) = receiver.recv().await {
+                        state.metrics.queue_depth.dec();
+                        state.metrics.in_flight_uploads.inc();
+
+                        let outcome = dispatch(&state, &job).await;
+                        state.metrics.in_flight_uploads.dec();
+
+                        if outcome.bytes > 0 {
+                            state.metrics.bytes_pushed.add(outcome.bytes as i64);
+                        }
+
+                        if outcome.gha_uploaded {
+                            state.metrics.gha_cache_uploaded.inc();
+                        }
+                        if outcome.gha_failed {
+                            state.metrics.gha_cache_failed.inc();
+                        }
+                        if outcome.flakehub_uploaded {
+                            state.metrics.flakehub_uploaded.inc();
+                        }
+                        if outcome.flakehub_failed {
+                            state.metrics.flakehub_failed.inc();
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        Self {
+            sender,
+            submitted: Arc::new(Mutex::new(HashSet::new())),
+            workers,
+            state,
+        }
+    }
+
+    /// Push paths onto the queue, skipping any path already submitted.
+    pub async fn enqueue(&self, store_paths: Vec<StorePath>) -> Result<()> {
+        let gha_configured = self.state.gha_cache.is_some();
+        let flakehub_configured = self.state.flakehub_state.read().await.is_some();
+
+        // Dedup under the lock, but release it before awaiting the (bounded)
+        // send below so a full channel only blocks this caller, not every
+        // other concurrent enqueue().
+        let num_requested = store_paths.len();
+        let to_send = {
+            let mut submitted = self.submitted.lock().await;
+            dedup(&mut submitted, store_paths)
+        };
+
+        let num_duplicates = (num_requested - to_send.len()) as i64;
+        if gha_configured {
+            self.state
+                .metrics
+                .gha_cache_skipped_duplicate
+                .add(num_duplicates);
+        }
+        if flakehub_configured {
+            self.state
+                .metrics
+                .flakehub_skipped_duplicate
+                .add(num_duplicates);
+        }
+
+        for store_path in to_send {
+            if gha_configured {
+                self.state.metrics.gha_cache_enqueued.inc();
+            }
+            if flakehub_configured {
+                self.state.metrics.flakehub_enqueued.inc();
+            }
+            self.state.metrics.queue_depth.inc();
+
+            self.sender
+                .send(Job { store_path })
+                .await
+                .map_err(|_| Error::Internal("upload queue closed".to_owned()))?;
+        }
+
+        Ok(())
+    }
+
+    /// A snapshot of current queue depth, in-flight uploads, and cumulative
+    /// per-backend counts, read straight off `state.metrics`.
+    pub fn status(&self) -> QueueStatus {
+        let metrics = &self.state.metrics;
+
+        QueueStatus {
+            queue_depth: metrics.queue_depth.get() as usize,
+            in_flight: metrics.in_flight_uploads.get() as usize,
+            bytes_pushed: metrics.bytes_pushed.get() as u64,
+            gha_cache: BackendSummary {
+                enqueued: metrics.gha_cache_enqueued.get() as usize,
+                uploaded: metrics.gha_cache_uploaded.get() as usize,
+                skipped_duplicate: metrics.gha_cache_skipped_duplicate.get() as usize,
+                failed: metrics.gha_cache_failed.get() as usize,
+            },
+            flakehub: BackendSummary {
+                enqueued: metrics.flakehub_enqueued.get() as usize,
+                uploaded: metrics.flakehub_uploaded.get() as usize,
+                skipped_duplicate: metrics.flakehub_skipped_duplicate.get() as usize,
+                failed: metrics.flakehub_failed.get() as usize,
+            },
+        }
+    }
+
+    /// Stop accepting new jobs, wait for the workers to drain the queue, then
+    /// finalize every configured backend and return the final status.
+    pub async fn shutdown(self, state: &State) -> Result<QueueStatus> {
+        self.sender.close();
+        for worker in self.workers {
+            let _ = worker.await;
+        }
+
+        if let Some(gha_cache) = &state.gha_cache {
+            tracing::info!("Waiting for GitHub action cache uploads to finish");
+            gha_cache.shutdown().await?;
+        }
+
+        if let Some(attic_state) = state.flakehub_state.write().await.take() {
+            tracing::info!("Waiting for FlakeHub cache uploads to finish");
+            let paths = attic_state.push_session.wait().await?;
+            tracing::warn!(?paths, "pushed these paths");
+        }
+
+        Ok(self.status())
+    }
+}
+
+#[derive(Debug, Default)]
+struct DispatchOutcome {
+    gha_uploaded: bool,
+    gha_failed: bool,
+    flakehub_uploaded: bool,
+    flakehub_failed: bool,
+    bytes: u64,
+}
+
+/// Dispatch a single job to every configured backend, recording the outcome
+/// for each independently so a failure in one backend doesn't hide a success
+/// in the other.
+async fn dispatch(state: &State, job: &Job) -> DispatchOutcome {
+    let mut outcome = DispatchOutcome::default();
+
+    if let Some(gha_cache) = &state.gha_cache {
+        match gha_cache
+            .enqueue_paths(state.store.clone(), vec![job.store_path.clone()])
+            .await
+        {
+            Ok(()) => outcome.gha_uploaded = true,
+            Err(e) => {
+                tracing::warn!(store_path = ?job.store_path, "GitHub Actions cache upload failed: {e}");
+                outcome.gha_failed = true;
+            }
+        }
+    }
+
+    if let Some(flakehub_state) = &*state.flakehub_state.read().await {
+        match crate::flakehub::enqueue_paths(flakehub_state, vec![job.store_path.clone()]).await {
+            Ok(()) => outcome.flakehub_uploaded = true,
+            Err(e) => {
+                tracing::warn!(store_path = ?job.store_path, "FlakeHub cache upload failed: {e}");
+                outcome.flakehub_failed = true;
+            }
+        }
+    }
+
+    if outcome.gha_uploaded || outcome.flakehub_uploaded {
+        outcome.bytes = state
+            .store
+            .query_path_info(job.store_path.clone())
+            .await
+            .map(|info| info.nar_size)
+            .unwrap_or(0);
+    }
+
+    outcome
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dedup_drops_already_submitted_items() {
+        let mut submitted = HashSet::new();
+
+        let first = dedup(&mut submitted, vec!["a", "b", "a"]);
+        assert_eq!(first, vec!["a", "b"]);
+
+        let second = dedup(&mut submitted, vec!["b", "c"]);
+        assert_eq!(second, vec!["c"]);
+    }
+}
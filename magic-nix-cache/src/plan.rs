@@ -0,0 +1,131 @@
+//! Expands requested roots to their full closure and drops paths already
+//! present in a configured upstream cache, mirroring attic's push "plan"
+//! step.
+
+use attic::nix_store::StorePath;
+
+use crate::error::Result;
+use crate::State;
+
+/// How many paths were requested, how many remained after expanding to the
+/// full closure, and how many of those were skipped because they're already
+/// cached upstream.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Plan {
+    pub num_requested: usize,
+    pub num_after_closure: usize,
+    pub num_skipped_upstream: usize,
+}
+
+impl Plan {
+    /// How many paths are actually left to enqueue.
+    pub fn num_enqueued(&self) -> usize {
+        self.num_after_closure - self.num_skipped_upstream
+    }
+}
+
+/// Expand `roots` to their full closure (unless `no_closure` is set) and
+/// drop any path already cached upstream (unless `ignore_upstream_cache_filter`
+/// is set). Returns the plan's counts alongside the paths that should
+/// actually be enqueued.
+pub async fn plan(
+    state: &State,
+    roots: Vec<StorePath>,
+    no_closure: bool,
+    ignore_upstream_cache_filter: bool,
+) -> Result<(Plan, Vec<StorePath>)> {
+    let num_requested = roots.len();
+
+    let closure = if no_closure {
+        roots
+    } else {
+        state.store.compute_fs_closure(roots, false, false).await?
+    };
+
+    let num_after_closure = closure.len();
+
+    let paths = if ignore_upstream_cache_filter {
+        closure
+    } else {
+        filter_upstream_cached(state, closure).await?
+    };
+
+    let num_skipped_upstream = num_after_closure - paths.len();
+
+    Ok((
+        Plan {
+            num_requested,
+            num_after_closure,
+            num_skipped_upstream,
+        },
+        paths,
+    ))
+}
+
+/// Drop any path already present in one of the configured upstream caches.
+async fn filter_upstream_cached(state: &State, paths: Vec<StorePath>) -> Result<Vec<StorePath>> {
+    if state.upstream_caches.is_empty() {
+        return Ok(paths);
+    }
+
+    let mut kept = Vec::with_capacity(paths.len());
+
+    for path in paths {
+        if !is_cached_upstream(state, &path).await {
+            kept.push(path);
+        }
+    }
+
+    Ok(kept)
+}
+
+/// Checks whether `path`'s narinfo is served by any configured upstream
+/// cache.
+async fn is_cached_upstream(state: &State, path: &StorePath) -> bool {
+    let hash = path.to_hash();
+
+    for substituter in &state.upstream_caches {
+        let url = format!("{substituter}/{hash}.narinfo");
+
+        let found = state
+            .http_client
+            .head(&url)
+            .send()
+            .await
+            .map(|response| response.status().is_success())
+            .unwrap_or(false);
+
+        if found {
+            return true;
+        }
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn num_enqueued_subtracts_upstream_skips_from_the_closure() {
+        let plan = Plan {
+            num_requested: 1,
+            num_after_closure: 10,
+            num_skipped_upstream: 4,
+        };
+
+        assert_eq!(plan.num_enqueued(), 6);
+    }
+
+    #[test]
+    fn num_enqueued_is_zero_when_everything_is_cached_upstream() {
+        let plan = Plan {
+            num_requested: 1,
+            num_after_closure: 3,
+            num_skipped_upstream: 3,
+        };
+
+        assert_eq!(plan.num_enqueued(), 0);
+    }
+}
@@ -0,0 +1,237 @@
+//! Watches the Nix store and enqueues paths for upload as soon as they
+//! finish building, instead of waiting for the `workflow-finish` store diff.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use attic::nix_store::StorePath;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::Mutex;
+
+use crate::error::{Error, Result};
+use crate::State;
+
+/// How long to wait after the most recent event implying a path may be
+/// settled before checking and enqueueing it; each new event for the same
+/// path restarts the wait, coalescing a burst of events (e.g. a `.lock`
+/// removal followed immediately by other directory churn) into one check.
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Watches `state.store`'s store directory and enqueues paths for upload as
+/// soon as they finish building.
+pub struct StoreWatcher {
+    // Kept alive only so the OS-level watch isn't torn down early; nothing
+    // reads from it directly.
+    _watcher: RecommendedWatcher,
+    debouncer: tokio::task::JoinHandle<()>,
+    pending: Arc<Mutex<HashMap<PathBuf, tokio::task::JoinHandle<()>>>>,
+    seen: Arc<Mutex<HashSet<StorePath>>>,
+}
+
+impl StoreWatcher {
+    /// Start watching the store directory non-recursively.
+    pub fn start(state: State) -> Result<Self> {
+        let store_dir = state.store.store_dir();
+        let seen = Arc::new(Mutex::new(HashSet::new()));
+        let pending = Arc::new(Mutex::new(HashMap::new()));
+
+        let (raw_tx, mut raw_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+            if let Ok(event) = event {
+                let _ = raw_tx.send(event);
+            }
+        })
+        .map_err(|e| Error::Internal(format!("failed to start store watcher: {e}")))?;
+
+        watcher
+            .watch(&store_dir, RecursiveMode::NonRecursive)
+            .map_err(|e| Error::Internal(format!("failed to watch {store_dir:?}: {e}")))?;
+
+        let debounce_seen = seen.clone();
+        let debounce_pending = pending.clone();
+        let debouncer = tokio::task::spawn(async move {
+            while let Some(event) = raw_rx.recv().await {
+                for path in event.paths {
+                    let Some(settled_path) = settled_candidate(&event.kind, &path) else {
+                        continue;
+                    };
+
+                    let state = state.clone();
+                    let seen = debounce_seen.clone();
+                    let path_for_task = settled_path.clone();
+                    let handle = tokio::task::spawn(async move {
+                        tokio::time::sleep(DEBOUNCE).await;
+                        settle(&state, &seen, &path_for_task).await;
+                    });
+
+                    let mut pending = debounce_pending.lock().await;
+                    if let Some(old) = pending.insert(settled_path, handle) {
+                        old.abort();
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            _watcher: watcher,
+            debouncer,
+            pending,
+            seen,
+        })
+    }
+
+    /// Stop watching the store and return the set of paths already caught,
+    /// so the `workflow-finish` store diff doesn't re-enqueue them.
+    pub async fn shutdown(self) -> HashSet<StorePath> {
+        self.debouncer.abort();
+        let _ = self.debouncer.await;
+
+        for (_, handle) in self.pending.lock().await.drain() {
+            handle.abort();
+            let _ = handle.await;
+        }
+
+        self.seen.lock().await.clone()
+    }
+}
+
+/// Filters out directory entries that can never be a finished store path:
+/// derivations and the various temporary names Nix uses while a path is
+/// still being built. `.lock` entries are handled separately by
+/// [`settled_candidate`], since their *removal* is itself the settle signal.
+fn is_candidate(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(OsStr::to_str) else {
+        return false;
+    };
+
+    !(name.ends_with(".lock")
+        || name.ends_with(".drv")
+        || name.ends_with(".check")
+        || name.contains(".tmp")
+        || name.contains("-chroot"))
+}
+
+/// Given a filesystem event for `path`, returns the store path it implies
+/// may now be settled, if any.
+///
+/// A path is settled either when its `.lock` file is removed (the normal
+/// case — build holds the lock, releases it when done), or when the store
+/// path itself is created with no lock ever observed (we raced ahead of the
+/// locker). Keying off the lock's removal rather than a fixed delay after
+/// `Create` means this doesn't depend on winning a race against however long
+/// the build takes to release the lock.
+fn settled_candidate(kind: &EventKind, path: &Path) -> Option<PathBuf> {
+    let name = path.file_name().and_then(OsStr::to_str)?;
+
+    if let Some(base) = name.strip_suffix(".lock") {
+        return matches!(kind, EventKind::Remove(_))
+            .then(|| path.with_file_name(base))
+            .filter(|settled| is_candidate(settled));
+    }
+
+    if matches!(kind, EventKind::Create(_)) && is_candidate(path) {
+        return Some(path.to_owned());
+    }
+
+    None
+}
+
+/// A store path is only done building once its `.lock` file has disappeared
+/// (or never existed, if we raced ahead of the locker). If so, resolve it and
+/// hand it off to the job queue directly, skipping the closure/upstream-cache
+/// planning done for `/api/enqueue-paths` — we already know exactly which
+/// single path just landed.
+async fn settle(state: &State, seen: &Arc<Mutex<HashSet<StorePath>>>, path: &Path) {
+    let lock_path = {
+        let mut lock_path = path.as_os_str().to_owned();
+        lock_path.push(".lock");
+        PathBuf::from(lock_path)
+    };
+
+    if lock_path.exists() {
+        return;
+    }
+
+    let Some(name) = path.file_name().and_then(OsStr::to_str) else {
+        return;
+    };
+
+    let store_path = match state.store.follow_store_path(name) {
+        Ok(store_path) => store_path,
+        Err(_) => return,
+    };
+
+    if seen.lock().await.contains(&store_path) {
+        return;
+    }
+
+    tracing::debug!(?store_path, "store watcher caught new path");
+
+    // Only record the path as seen once it is actually on the queue, so a
+    // failed enqueue leaves it for the `workflow-finish` store diff.
+    match crate::api::enqueue_paths_raw(state, vec![store_path.clone()]).await {
+        Ok(()) => {
+            seen.lock().await.insert(store_path);
+        }
+        Err(e) => tracing::warn!("store watcher failed to enqueue path: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_candidate_filters_build_noise() {
+        assert!(is_candidate(Path::new(
+            "/nix/store/abc123-hello-1.0"
+        )));
+        assert!(!is_candidate(Path::new("/nix/store/abc123-hello-1.0.lock")));
+        assert!(!is_candidate(Path::new("/nix/store/abc123-hello-1.0.drv")));
+        assert!(!is_candidate(Path::new(
+            "/nix/store/abc123-hello-1.0.check"
+        )));
+        assert!(!is_candidate(Path::new(
+            "/nix/store/.tmp-12345-abc123-hello-1.0"
+        )));
+        assert!(!is_candidate(Path::new(
+            "/nix/store/abc123-hello-1.0-chroot"
+        )));
+    }
+
+    #[test]
+    fn settled_candidate_keys_off_lock_removal() {
+        let lock = Path::new("/nix/store/abc123-hello-1.0.lock");
+
+        assert_eq!(
+            settled_candidate(&EventKind::Remove(notify::event::RemoveKind::Any), lock),
+            Some(PathBuf::from("/nix/store/abc123-hello-1.0"))
+        );
+
+        // A lock being created (build starting) isn't a settle signal.
+        assert_eq!(
+            settled_candidate(&EventKind::Create(notify::event::CreateKind::Any), lock),
+            None
+        );
+    }
+
+    #[test]
+    fn settled_candidate_falls_back_to_create_with_no_lock() {
+        let path = Path::new("/nix/store/abc123-hello-1.0");
+
+        assert_eq!(
+            settled_candidate(&EventKind::Create(notify::event::CreateKind::Any), path),
+            Some(path.to_owned())
+        );
+
+        assert_eq!(
+            settled_candidate(&EventKind::Remove(notify::event::RemoveKind::Any), path),
+            None
+        );
+    }
+}